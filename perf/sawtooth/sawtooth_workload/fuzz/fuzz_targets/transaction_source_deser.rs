@@ -0,0 +1,13 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use sawtooth_workload::batch_gen::fuzz_parse_transaction_source;
+
+// Feeds arbitrary bytes through `TransactionSource`. The only contract under
+// test is "never panics, never allocates unboundedly" -- any `Err` is a pass.
+fuzz_target!(|data: &[u8]| {
+    let mut reader = Cursor::new(data);
+    let _ = fuzz_parse_transaction_source(&mut reader);
+});
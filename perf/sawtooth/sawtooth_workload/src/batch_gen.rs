@@ -18,7 +18,11 @@
 //! Tools for generating signed batches from a stream of transactions
 
 extern crate protobuf;
+extern crate rayon;
+extern crate num_cpus;
+extern crate ahash;
 
+use std::collections::VecDeque;
 use std::error;
 use std::fmt;
 use std::io::Read;
@@ -26,20 +30,94 @@ use std::io::Write;
 use std::marker::PhantomData;
 
 use sawtooth_sdk::messages::transaction::Transaction;
+use sawtooth_sdk::messages::transaction::TransactionHeader;
 use sawtooth_sdk::messages::batch::Batch;
 use sawtooth_sdk::messages::batch::BatchHeader;
+use sawtooth_sdk::signing;
+use sawtooth_sdk::signing::Context;
+use sawtooth_sdk::signing::secp256k1::Secp256k1Context;
+use sawtooth_sdk::signing::secp256k1::Secp256k1PrivateKey;
+use sawtooth_sdk::signing::secp256k1::Secp256k1PublicKey;
 use self::protobuf::Message;
-use self::protobuf::MessageStatic;
+use self::rayon::prelude::*;
+use self::rayon::ThreadPoolBuilder;
+
+/// Number of `max_batch_size`-sized batches to read ahead before handing a
+/// slice of batches to the worker pool to sign, so that each call to
+/// `pool.install` has enough work to amortize its overhead.
+const READAHEAD_BATCHES: usize = 8;
+
+lazy_static! {
+    /// Default signing pool, sized to the number of available cores and
+    /// built lazily the first time `generate_signed_batches_parallel` is
+    /// called with `num_threads == 0`.
+    static ref DEFAULT_SIGNING_POOL: rayon::ThreadPool = ThreadPoolBuilder::new()
+        .num_threads(num_cpus::get())
+        .build()
+        .expect("Unable to build default signing thread pool");
+}
+
+
+/// Wraps a secp256k1 private key and signs batch headers on its behalf.
+pub struct BatchSigner<'a> {
+    context: &'a signing::Context,
+    private_key: Box<signing::PrivateKey>,
+    /// Hex-encoded copy of `private_key`, kept alongside the boxed key so
+    /// that `generate_signed_batches_parallel` can rebuild an independent,
+    /// owned secp256k1 context and key inside each worker thread instead of
+    /// sharing this `BatchSigner` across threads: `signing::Context` and
+    /// `signing::PrivateKey` are not declared `Send`/`Sync` upstream, so
+    /// they cannot cross a thread boundary by reference.
+    private_key_hex: String,
+}
+
+impl<'a> BatchSigner<'a> {
+    /// Creates a new `BatchSigner` from a secp256k1 context and private key.
+    pub fn new(context: &'a signing::Context, private_key: Box<signing::PrivateKey>) -> Self {
+        let private_key_hex = private_key.as_hex();
+        BatchSigner {
+            context,
+            private_key,
+            private_key_hex,
+        }
+    }
 
+    /// Returns the hex-encoded public key associated with this signer.
+    fn public_key(&self) -> Result<String, BatchingError> {
+        self.context
+            .get_public_key(self.private_key.as_ref())
+            .map(|pub_key| pub_key.as_hex())
+            .map_err(|_| BatchingError::SigningError)
+    }
+
+    /// Signs the given bytes, returning the hex-encoded signature.
+    fn sign(&self, bytes: &[u8]) -> Result<String, BatchingError> {
+        self.context
+            .sign(bytes, self.private_key.as_ref())
+            .map_err(|_| BatchingError::SigningError)
+    }
+
+    /// Returns this signer's private key, hex-encoded.  Used by
+    /// `generate_signed_batches_parallel`, which cannot share this
+    /// `BatchSigner`'s own `Context`/`PrivateKey` across worker threads; see
+    /// `sign_batch_standalone`.
+    fn private_key_hex(&self) -> &str {
+        &self.private_key_hex
+    }
+}
 
 /// Generates signed batches from a stream of length-delimited transactions.
 /// Constrains the batches to `max_batch_size` number of transactions per
 /// batch.  The resulting batches are written in a length-delimited fashion to
 /// the given writer.
-pub fn generate_signed_batches<'a>(reader: &'a mut Read, writer: &'a mut Write, max_batch_size: usize)
-    -> Result<(), BatchingError>
+pub fn generate_signed_batches<'a, 'b>(
+    reader: &'a mut Read,
+    writer: &'a mut Write,
+    max_batch_size: usize,
+    signer: &'b BatchSigner<'b>,
+) -> Result<(), BatchingError>
 {
-    let mut producer = SignedBatchProducer::new(reader, max_batch_size);
+    let mut producer = SignedBatchProducer::new(reader, max_batch_size, signer);
     loop {
         match producer.next_batch() {
             Ok(Some(batch)) => {
@@ -55,45 +133,360 @@ pub fn generate_signed_batches<'a>(reader: &'a mut Read, writer: &'a mut Write,
     Ok(())
 }
 
+/// Generates signed batches in parallel, using a bounded worker pool to sign
+/// batch headers, each batch holding at most `max_batch_size` transactions.
+/// Reads ahead `READAHEAD_BATCHES` batches' worth of
+/// transactions so the pool has enough batches to sign concurrently, then
+/// writes them out in the same order they were read.
+///
+/// `max_batch_bytes` and `verify_transactions` give this entry point parity
+/// with `SignedBatchProducer::set_max_batch_bytes`/`set_verify_transactions`:
+/// pass `Some(budget)` to close a batch early once its serialized
+/// transactions would exceed `budget` bytes, and `true` to drop invalid or
+/// duplicate transactions before they reach a batch. Pass `None`/`false` to
+/// match the unconstrained behavior of `generate_signed_batches`.
+///
+/// Passing `num_threads == 1` skips the pool entirely and drives a
+/// `SignedBatchProducer` directly instead, applying the same two options.
+pub fn generate_signed_batches_parallel<'a, 'b>(
+    reader: &'a mut Read,
+    writer: &'a mut Write,
+    max_batch_size: usize,
+    signer: &'b BatchSigner<'b>,
+    num_threads: usize,
+    max_batch_bytes: Option<u64>,
+    verify_transactions: bool,
+) -> Result<(), BatchingError>
+{
+    if num_threads == 1 {
+        let mut producer = SignedBatchProducer::new(reader, max_batch_size, signer);
+        if let Some(max_batch_bytes) = max_batch_bytes {
+            producer.set_max_batch_bytes(max_batch_bytes);
+        }
+        if verify_transactions {
+            producer.set_verify_transactions();
+        }
+
+        loop {
+            match producer.next_batch()? {
+                Some(batch) => {
+                    if let Err(err) = batch.write_length_delimited_to_writer(writer) {
+                        return Err(BatchingError::MessageError(err));
+                    }
+                },
+                None => break,
+            }
+        }
+
+        return Ok(());
+    }
+
+    let owned_pool;
+    let pool: &rayon::ThreadPool = if num_threads == 0 {
+        &DEFAULT_SIGNING_POOL
+    } else {
+        owned_pool = ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .map_err(|_| BatchingError::SigningError)?;
+        &owned_pool
+    };
+
+    let mut transaction_source: TransactionSource = LengthDelimitedMessageSource::new(reader);
+    let pubkey = signer.public_key()?;
+    let private_key_hex = signer.private_key_hex().to_string();
+    let mut verifier = if verify_transactions {
+        Some(TransactionVerifier::new())
+    } else {
+        None
+    };
+    let readahead_size = max_batch_size.saturating_mul(READAHEAD_BATCHES);
+
+    loop {
+        let txns = transaction_source.next(readahead_size)?;
+
+        if txns.is_empty() {
+            break;
+        }
+
+        let txns = match verifier {
+            Some(ref mut verifier) => pool.install(|| verifier.filter(txns)),
+            None => txns,
+        };
+
+        if txns.is_empty() {
+            continue;
+        }
+
+        let mut batches: Vec<Batch> = chunk_transactions(txns, max_batch_size, max_batch_bytes)
+            .into_iter()
+            .map(|chunk| build_unsigned_batch(&chunk, &pubkey))
+            .collect();
+
+        let sign_results: Vec<Result<(), BatchingError>> = pool.install(|| {
+            batches
+                .par_iter_mut()
+                .map(|batch| sign_batch_standalone(batch, &private_key_hex))
+                .collect()
+        });
+
+        for result in sign_results {
+            result?;
+        }
+
+        for batch in &batches {
+            if let Err(err) = batch.write_length_delimited_to_writer(writer) {
+                return Err(BatchingError::MessageError(err));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits `txns` into batches of at most `max_batch_size` transactions,
+/// additionally closing a batch early -- before `max_batch_size` is reached
+/// -- if the next transaction would push it over `max_batch_bytes` (when
+/// given).  A transaction that alone exceeds `max_batch_bytes` is still
+/// emitted as the sole member of its own batch. Mirrors the batching
+/// behavior of `SignedBatchProducer::next_batch`.
+fn chunk_transactions(
+    txns: Vec<Transaction>,
+    max_batch_size: usize,
+    max_batch_bytes: Option<u64>,
+) -> Vec<Vec<Transaction>> {
+    let mut batches = Vec::new();
+    let mut current: Vec<Transaction> = Vec::new();
+    let mut current_bytes: u64 = 0;
+
+    for txn in txns {
+        let txn_bytes = u64::from(txn.compute_size());
+
+        if let Some(max_batch_bytes) = max_batch_bytes {
+            if current.is_empty() && txn_bytes > max_batch_bytes {
+                warn!(
+                    "Transaction {} is {} bytes, exceeding max_batch_bytes of {}; \
+                     emitting it as its own oversized batch",
+                    txn.get_header_signature(), txn_bytes, max_batch_bytes,
+                );
+                batches.push(vec![txn]);
+                continue;
+            }
+
+            if current_bytes + txn_bytes > max_batch_bytes || current.len() >= max_batch_size {
+                batches.push(std::mem::replace(&mut current, Vec::new()));
+                current_bytes = 0;
+            }
+        } else if current.len() >= max_batch_size {
+            batches.push(std::mem::replace(&mut current, Vec::new()));
+            current_bytes = 0;
+        }
+
+        current_bytes += txn_bytes;
+        current.push(txn);
+    }
+
+    if !current.is_empty() {
+        batches.push(current);
+    }
+
+    batches
+}
+
+/// Builds an unsigned `Batch` from a slice of transactions and the signer's
+/// public key, leaving only `header_signature` to be filled in by `sign_batch`.
+fn build_unsigned_batch(txns: &[Transaction], pubkey: &str) -> Batch {
+    let mut batch_header = BatchHeader::new();
+    let txn_ids = txns.iter()
+        .map(|txn| txn.get_header_signature().to_string())
+        .collect();
+    batch_header.set_transaction_ids(protobuf::RepeatedField::from_vec(txn_ids));
+    batch_header.set_signer_pubkey(pubkey.to_string());
+
+    let mut batch = Batch::new();
+    batch.set_header(batch_header.write_to_bytes().unwrap());
+    batch.set_transactions(protobuf::RepeatedField::from_vec(txns.to_vec()));
+    batch
+}
+
+/// Signs an unsigned batch's header in place, setting `header_signature`.
+fn sign_batch(batch: &mut Batch, signer: &BatchSigner) -> Result<(), BatchingError> {
+    let signature = signer.sign(batch.get_header())?;
+    batch.set_header_signature(signature);
+    Ok(())
+}
+
+/// Signs an unsigned batch's header in place using a freshly-constructed
+/// secp256k1 context and a private key rebuilt from `private_key_hex`,
+/// instead of a shared `BatchSigner`.  `generate_signed_batches_parallel`'s
+/// worker closures run on a different thread than the one that owns the
+/// `BatchSigner`, and `signing::Context`/`signing::PrivateKey` are not
+/// declared `Send`/`Sync` upstream, so the signer's own context and key
+/// cannot cross that boundary -- a fresh context and a key rebuilt from hex
+/// are cheap enough to construct per call and share nothing.
+fn sign_batch_standalone(batch: &mut Batch, private_key_hex: &str) -> Result<(), BatchingError> {
+    let context = Secp256k1Context::new();
+    let private_key = Secp256k1PrivateKey::from_hex(private_key_hex)
+        .map_err(|_| BatchingError::SigningError)?;
+    let signature = context
+        .sign(batch.get_header(), &private_key)
+        .map_err(|_| BatchingError::SigningError)?;
+    batch.set_header_signature(signature);
+    Ok(())
+}
+
+/// Returns whether `txn`'s `header_signature` is a valid signature, by its
+/// own `header`'s `signer_pubkey`, over that header.  Builds its own
+/// secp256k1 context rather than taking one as an argument: `filter` below
+/// calls this from multiple threads at once, and `signing::Context` is not
+/// declared `Sync` upstream, so no single context could be shared across
+/// that call.
+fn transaction_is_valid(txn: &Transaction) -> bool {
+    let header: TransactionHeader = match protobuf::parse_from_bytes(txn.get_header()) {
+        Ok(header) => header,
+        Err(_) => return false,
+    };
+
+    let public_key = match Secp256k1PublicKey::from_hex(header.get_signer_pubkey()) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+
+    let context = Secp256k1Context::new();
+    context
+        .verify(txn.get_header_signature(), txn.get_header(), &public_key)
+        .unwrap_or(false)
+}
+
+/// Verifies transaction signatures and drops duplicate transactions before
+/// they can reach a batch, checking each transaction's signature in
+/// parallel across a pulled slice and deduping against every
+/// `header_signature` already seen in this run.
+struct TransactionVerifier {
+    seen_signatures: ahash::AHashSet<String>,
+    invalid_count: usize,
+    duplicate_count: usize,
+}
+
+impl TransactionVerifier {
+    fn new() -> Self {
+        TransactionVerifier {
+            seen_signatures: ahash::AHashSet::default(),
+            invalid_count: 0,
+            duplicate_count: 0,
+        }
+    }
+
+    /// Filters `txns` down to those that are validly signed and not repeats
+    /// of a transaction already passed through this verifier, updating the
+    /// dropped/duplicate counters as it goes.
+    fn filter(&mut self, txns: Vec<Transaction>) -> Vec<Transaction> {
+        let valid: Vec<bool> = txns.par_iter()
+            .map(|txn| transaction_is_valid(txn))
+            .collect();
+
+        let mut survivors = Vec::with_capacity(txns.len());
+        for (txn, is_valid) in txns.into_iter().zip(valid) {
+            if !is_valid {
+                self.invalid_count += 1;
+                continue;
+            }
+            if !self.seen_signatures.insert(txn.get_header_signature().to_string()) {
+                self.duplicate_count += 1;
+                continue;
+            }
+            survivors.push(txn);
+        }
+        survivors
+    }
+}
+
+/// Drains every length-delimited `Transaction` out of `reader`, discarding
+/// the results.
+///
+/// This only exists so that the `transaction_source_deser` fuzz target in
+/// `fuzz/` can drive `LengthDelimitedMessageSource` without that type being
+/// otherwise exposed outside this module; it should never be called from
+/// production code.
+#[doc(hidden)]
+pub fn fuzz_parse_transaction_source(reader: &mut Read) -> Result<(), BatchingError> {
+    let mut source: TransactionSource = LengthDelimitedMessageSource::new(reader);
+    loop {
+        let txns = source.next(16)?;
+        if txns.is_empty() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Default maximum length, in bytes, of a single length-delimited message.
+/// Chosen to comfortably fit any legitimate transaction or batch while still
+/// bounding the allocation a hostile length prefix can trigger.
+pub const DEFAULT_MAX_MESSAGE_LEN: u32 = 4 * 1024 * 1024;
+
 /// Decodes Protocol Buffer messages from a length-delimited input reader.
 struct LengthDelimitedMessageSource<'a, T: 'a> {
     source: protobuf::CodedInputStream<'a>,
+    max_message_len: u32,
     phantom: PhantomData<&'a T>,
 }
 
 impl<'a, T> LengthDelimitedMessageSource<'a, T>
-    where T: Message + MessageStatic
+    where T: Message
 {
-    /// Creates a new `LengthDelimitedMessageSource` from a given reader.
+    /// Creates a new `LengthDelimitedMessageSource` from a given reader, with
+    /// `max_message_len` set to `DEFAULT_MAX_MESSAGE_LEN`.
     pub fn new(source: &'a mut Read) -> Self {
         let source = protobuf::CodedInputStream::new(source);
         LengthDelimitedMessageSource {
             source,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
             phantom: PhantomData,
         }
     }
 
+    /// Overrides the maximum allowed length of a single message.  A length
+    /// prefix larger than this is rejected with `BatchingError::MessageTooLarge`
+    /// before any allocation is made for it.
+    pub fn set_max_message_len(&mut self, max_message_len: u32) {
+        self.max_message_len = max_message_len;
+    }
+
     /// Returns the next set of messages.
     /// The vector of messages will contain up to `max_msgs` number of
     /// messages.  An empty vector indicates that the source has been consumed.
     pub fn next(&mut self, max_msgs: usize)
-        -> Result<Vec<T>, protobuf::ProtobufError>
+        -> Result<Vec<T>, BatchingError>
     {
         let mut results = Vec::with_capacity(max_msgs);
         for _ in 0..max_msgs {
-            if self.source.eof()? {
-                break;
+            match self.next_one()? {
+                Some(msg) => results.push(msg),
+                None => break,
             }
-
-            // read the delimited length
-            let next_len = try!(self.source.read_raw_varint32());
-            let buf = try!(self.source.read_raw_bytes(next_len));
-            
-            let msg = try!(protobuf::parse_from_bytes(&buf));
-            results.push(msg);
         }
         Ok(results)
     }
+
+    /// Returns the next single message, or `None` if the source has been
+    /// consumed.
+    pub fn next_one(&mut self) -> Result<Option<T>, BatchingError> {
+        if self.source.eof()? {
+            return Ok(None);
+        }
+
+        // read the delimited length, rejecting it before allocating a
+        // buffer if it exceeds the configured maximum
+        let next_len = self.source.read_raw_varint32()?;
+        if next_len > self.max_message_len {
+            return Err(BatchingError::MessageTooLarge(next_len));
+        }
+        let buf = self.source.read_raw_bytes(next_len)?;
+
+        let msg = protobuf::parse_from_bytes(&buf)?;
+        Ok(Some(msg))
+    }
 }
 
 type TransactionSource<'a> = LengthDelimitedMessageSource<'a, Transaction>;
@@ -103,6 +496,15 @@ type TransactionSource<'a> = LengthDelimitedMessageSource<'a, Transaction>;
 pub enum BatchingError {
     MessageError(protobuf::ProtobufError),
     SigningError,
+    /// A length-delimited message's prefix exceeded the configured
+    /// `max_message_len`, given here in bytes.
+    MessageTooLarge(u32),
+}
+
+impl From<protobuf::ProtobufError> for BatchingError {
+    fn from(err: protobuf::ProtobufError) -> Self {
+        BatchingError::MessageError(err)
+    }
 }
 
 impl fmt::Display for BatchingError {
@@ -111,6 +513,8 @@ impl fmt::Display for BatchingError {
             BatchingError::MessageError(ref err) =>
                 write!(f, "Error occurred reading messages: {}", err),
             BatchingError::SigningError => write!(f, "Unable to sign batch"),
+            BatchingError::MessageTooLarge(len) =>
+                write!(f, "Message length {} exceeds the maximum allowed length", len),
         }
     }
 }
@@ -120,6 +524,7 @@ impl error::Error for BatchingError {
         match *self {
             BatchingError::MessageError(ref err) => err.description(),
             BatchingError::SigningError => "Unable to sign batch",
+            BatchingError::MessageTooLarge(_) => "Message length exceeds the maximum allowed length",
         }
     }
 
@@ -127,53 +532,145 @@ impl error::Error for BatchingError {
         match *self {
             BatchingError::MessageError(ref err) => Some(err),
             BatchingError::SigningError => None,
+            BatchingError::MessageTooLarge(_) => None,
         }
     }
 }
 
 /// Produces signed batches from a length-delimited source of Transactions.
-pub struct SignedBatchProducer<'a> {
+pub struct SignedBatchProducer<'a, 'b> {
     transaction_source: TransactionSource<'a>,
     max_batch_size: usize,
+    signer: &'b BatchSigner<'b>,
+    max_batch_bytes: Option<u64>,
+    queue: VecDeque<Transaction>,
+    verifier: Option<TransactionVerifier>,
 }
 
 /// Resulting batch or error.
 pub type BatchResult = Result<Option<Batch>, BatchingError>;
 
-impl<'a> SignedBatchProducer<'a> {
+impl<'a, 'b> SignedBatchProducer<'a, 'b> {
 
-    /// Creates a new `SignedBatchProducer` with a given Transaction source and
-    /// a max number of transactions per batch.
-    pub fn new(source: &'a mut Read, max_batch_size: usize) -> Self {
+    /// Creates a new `SignedBatchProducer` with a given Transaction source, a
+    /// max number of transactions per batch, and the signer used to sign each
+    /// batch header.
+    pub fn new(source: &'a mut Read, max_batch_size: usize, signer: &'b BatchSigner<'b>) -> Self {
         let transaction_source = LengthDelimitedMessageSource::new(source);
         SignedBatchProducer {
             transaction_source,
             max_batch_size,
+            signer,
+            max_batch_bytes: None,
+            queue: VecDeque::new(),
+            verifier: None,
         }
     }
 
+    /// Overrides the maximum allowed length, in bytes, of a single incoming
+    /// transaction message. See `DEFAULT_MAX_MESSAGE_LEN`.
+    pub fn set_max_message_len(&mut self, max_message_len: u32) {
+        self.transaction_source.set_max_message_len(max_message_len);
+    }
+
+    /// Constrains batches to at most `max_batch_bytes` of serialized
+    /// transaction content, closing a batch early -- before `max_batch_size`
+    /// is reached -- if the next transaction would exceed the budget.  A
+    /// transaction that alone exceeds the budget is still emitted, as the
+    /// sole member of its own batch.
+    pub fn set_max_batch_bytes(&mut self, max_batch_bytes: u64) {
+        self.max_batch_bytes = Some(max_batch_bytes);
+    }
+
+    /// Enables a verification pass that checks each incoming transaction's
+    /// `header_signature` against its own `signer_pubkey` before it can
+    /// reach a batch, and drops any transaction whose signature was already
+    /// emitted earlier in this run. Use `invalid_transaction_count` and
+    /// `duplicate_transaction_count` to see what was dropped.
+    pub fn set_verify_transactions(&mut self) {
+        self.verifier = Some(TransactionVerifier::new());
+    }
+
+    /// Number of transactions dropped for failing signature verification.
+    /// Always `0` unless `set_verify_transactions` was called.
+    pub fn invalid_transaction_count(&self) -> usize {
+        self.verifier.as_ref().map_or(0, |verifier| verifier.invalid_count)
+    }
+
+    /// Number of transactions dropped as duplicates of an earlier
+    /// transaction in this run. Always `0` unless `set_verify_transactions`
+    /// was called.
+    pub fn duplicate_transaction_count(&self) -> usize {
+        self.verifier.as_ref().map_or(0, |verifier| verifier.duplicate_count)
+    }
+
+    /// Pulls the next `max_batch_size` transactions' worth of work from the
+    /// underlying source into `queue`, running them through the verifier
+    /// first if one is configured. Returns `false` once the source is
+    /// exhausted.
+    fn refill(&mut self) -> Result<bool, BatchingError> {
+        let pulled = self.transaction_source.next(self.max_batch_size)?;
+        if pulled.is_empty() {
+            return Ok(false);
+        }
+
+        let survivors = match self.verifier {
+            Some(ref mut verifier) => verifier.filter(pulled),
+            None => pulled,
+        };
+
+        self.queue.extend(survivors);
+        Ok(true)
+    }
+
     /// Gets the next BatchResult.
     /// `Ok(None)` indicates that the underlying source has been consumed.
     pub fn next_batch(&mut self) -> BatchResult {
-        let txns = match self.transaction_source.next(self.max_batch_size) {
-            Ok(txns) => txns,
-            Err(err) => return Err(BatchingError::MessageError(err)),
-        };
+        let mut txns = Vec::new();
+        let mut batch_bytes: u64 = 0;
 
-        if txns.len() == 0 {
-            return Ok(None);
+        while txns.len() < self.max_batch_size {
+            if self.queue.is_empty() && !self.refill()? {
+                break;
+            }
+
+            let txn = match self.queue.pop_front() {
+                Some(txn) => txn,
+                None => continue,
+            };
+
+            if let Some(max_batch_bytes) = self.max_batch_bytes {
+                let txn_bytes = u64::from(txn.compute_size());
+
+                if txns.is_empty() && txn_bytes > max_batch_bytes {
+                    warn!(
+                        "Transaction {} is {} bytes, exceeding max_batch_bytes of {}; \
+                         emitting it as its own oversized batch",
+                        txn.get_header_signature(), txn_bytes, max_batch_bytes,
+                    );
+                    txns.push(txn);
+                    break;
+                }
+
+                if batch_bytes + txn_bytes > max_batch_bytes {
+                    self.queue.push_front(txn);
+                    break;
+                }
+
+                batch_bytes += txn_bytes;
+            }
+
+            txns.push(txn);
         }
 
-        let mut batch_header = BatchHeader::new();
+        if txns.is_empty() {
+            return Ok(None);
+        }
 
-        // set signer_pubkey
-        let txn_ids = txns.iter().cloned().map(|mut txn| txn.take_header_signature()).collect();
-        batch_header.set_transaction_ids(protobuf::RepeatedField::from_vec(txn_ids));
+        let pubkey = self.signer.public_key()?;
+        let mut batch = build_unsigned_batch(&txns, &pubkey);
+        sign_batch(&mut batch, self.signer)?;
 
-        let mut batch = Batch::new();
-        batch.set_header(batch_header.write_to_bytes().unwrap());
-        batch.set_transactions(protobuf::RepeatedField::from_vec(txns));
-            
         Ok(Some(batch))
     }
 }
@@ -184,14 +681,23 @@ mod tests {
     use super::LengthDelimitedMessageSource;
     use super::TransactionSource;
     use super::SignedBatchProducer;
+    use super::BatchSigner;
     use std::io::{Cursor, Write};
     use sawtooth_sdk::messages::transaction::{Transaction, TransactionHeader};
     use sawtooth_sdk::messages::batch::{Batch, BatchHeader};
+    use sawtooth_sdk::signing;
     use super::protobuf;
     use super::protobuf::Message;
 
     type BatchSource<'a> = LengthDelimitedMessageSource<'a, Batch>;
 
+    /// Builds a `BatchSigner` backed by a fresh random secp256k1 key, for use
+    /// in tests that do not care which key is used, only that signing works.
+    fn test_signer<'a>(context: &'a signing::Context) -> BatchSigner<'a> {
+        let private_key = context.new_random_private_key().expect("key generation");
+        BatchSigner::new(context, private_key)
+    }
+
     #[test]
     fn empty_transaction_source() {
         let encoded_bytes: Vec<u8> = Vec::new();
@@ -222,14 +728,32 @@ mod tests {
         assert_eq!(txns.len(), 1);
     }
 
+    #[test]
+    fn rejects_oversized_length_prefix() {
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+        write_txn_with_sig("sig1", &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+
+        let mut txn_stream: TransactionSource = LengthDelimitedMessageSource::new(&mut source);
+        txn_stream.set_max_message_len(4);
+
+        match txn_stream.next(1) {
+            Err(super::BatchingError::MessageTooLarge(_)) => (),
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+    }
+
     #[test]
     fn signed_batches_empty_transactions() {
         let encoded_bytes: Vec<u8> = Vec::new();
         let mut source = Cursor::new(encoded_bytes);
 
-        let mut producer = SignedBatchProducer::new(&mut source, 2);
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        let mut producer = SignedBatchProducer::new(&mut source, 2, &signer);
         let batch_result = producer.next_batch().unwrap();
-        
+
         assert_eq!(batch_result, None);
     }
 
@@ -240,7 +764,9 @@ mod tests {
 
         let mut source = Cursor::new(encoded_bytes);
 
-        let mut producer = SignedBatchProducer::new(&mut source, 2);
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        let mut producer = SignedBatchProducer::new(&mut source, 2, &signer);
         let mut batch_result = producer.next_batch().unwrap();
         assert!(batch_result.is_some());
 
@@ -249,6 +775,8 @@ mod tests {
         let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
         assert_eq!(batch_header.transaction_ids.len(), 1);
         assert_eq!(batch_header.transaction_ids[0], String::from("sig1"));
+        assert_eq!(batch_header.signer_pubkey, signer.public_key().unwrap());
+        assert!(!batch.header_signature.is_empty());
 
         // test exhaustion
         batch_result = producer.next_batch().unwrap();
@@ -265,7 +793,9 @@ mod tests {
 
         let mut source = Cursor::new(encoded_bytes);
 
-        let mut producer = SignedBatchProducer::new(&mut source, 2);
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        let mut producer = SignedBatchProducer::new(&mut source, 2, &signer);
         let mut batch_result = producer.next_batch().unwrap();
         assert!(batch_result.is_some());
 
@@ -291,6 +821,56 @@ mod tests {
         assert_eq!(batch_result, None);
     }
 
+    #[test]
+    fn signed_batches_max_batch_bytes_splits_before_max_batch_size() {
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+
+        write_txn_with_sig("sig1", &mut encoded_bytes);
+        write_txn_with_sig("sig2", &mut encoded_bytes);
+        write_txn_with_sig("sig3", &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        let mut producer = SignedBatchProducer::new(&mut source, 10, &signer);
+
+        let txn_bytes = u64::from(make_txn("sig1").compute_size());
+        producer.set_max_batch_bytes(txn_bytes);
+
+        for sig in &["sig1", "sig2", "sig3"] {
+            let batch = producer.next_batch().unwrap().expect("expected a batch");
+            let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+            assert_eq!(batch_header.transaction_ids.len(), 1);
+            assert_eq!(batch_header.transaction_ids[0], String::from(*sig));
+        }
+
+        assert_eq!(producer.next_batch().unwrap(), None);
+    }
+
+    #[test]
+    fn signed_batches_oversized_transaction_emitted_alone() {
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+        write_txn_with_sig("sig1", &mut encoded_bytes);
+        write_txn_with_sig("sig2", &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        let mut producer = SignedBatchProducer::new(&mut source, 10, &signer);
+        producer.set_max_batch_bytes(1);
+
+        for sig in &["sig1", "sig2"] {
+            let batch = producer.next_batch().unwrap().expect("expected a batch");
+            let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+            assert_eq!(batch_header.transaction_ids.len(), 1);
+            assert_eq!(batch_header.transaction_ids[0], String::from(*sig));
+        }
+
+        assert_eq!(producer.next_batch().unwrap(), None);
+    }
+
     #[test]
     fn generate_signed_batches() {
         let mut encoded_bytes: Vec<u8> = Vec::new();
@@ -303,7 +883,9 @@ mod tests {
         let output_bytes: Vec<u8> = Vec::new();
         let mut output = Cursor::new(output_bytes);
 
-        super::generate_signed_batches(&mut source, &mut output, 2)
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        super::generate_signed_batches(&mut source, &mut output, 2, &signer)
              .expect("Should have generated batches!");
 
         // reset for reading
@@ -323,6 +905,126 @@ mod tests {
         assert_eq!(batch_header.transaction_ids[0], String::from("sig3"));
     }
 
+    #[test]
+    fn generate_signed_batches_parallel() {
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+
+        write_txn_with_sig("sig1", &mut encoded_bytes);
+        write_txn_with_sig("sig2", &mut encoded_bytes);
+        write_txn_with_sig("sig3", &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+        let output_bytes: Vec<u8> = Vec::new();
+        let mut output = Cursor::new(output_bytes);
+
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        super::generate_signed_batches_parallel(
+            &mut source, &mut output, 2, &signer, 2, None, false,
+        ).expect("Should have generated batches!");
+
+        output.set_position(0);
+        let mut batch_source: BatchSource =
+            LengthDelimitedMessageSource::new(&mut output);
+
+        let batch = &(batch_source.next(1).unwrap())[0];
+        let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+        assert_eq!(batch_header.transaction_ids.len(), 2);
+        assert_eq!(batch_header.transaction_ids[0], String::from("sig1"));
+        assert_eq!(batch_header.transaction_ids[1], String::from("sig2"));
+        assert!(!batch.header_signature.is_empty());
+
+        let batch = &(batch_source.next(1).unwrap())[0];
+        let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+        assert_eq!(batch_header.transaction_ids.len(), 1);
+        assert_eq!(batch_header.transaction_ids[0], String::from("sig3"));
+    }
+
+    #[test]
+    fn generate_signed_batches_parallel_single_thread_falls_back_to_serial() {
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+        write_txn_with_sig("sig1", &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+        let output_bytes: Vec<u8> = Vec::new();
+        let mut output = Cursor::new(output_bytes);
+
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        super::generate_signed_batches_parallel(
+            &mut source, &mut output, 2, &signer, 1, None, false,
+        ).expect("Should have generated batches!");
+
+        output.set_position(0);
+        let mut batch_source: BatchSource =
+            LengthDelimitedMessageSource::new(&mut output);
+
+        let batch = &(batch_source.next(1).unwrap())[0];
+        let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+        assert_eq!(batch_header.transaction_ids.len(), 1);
+        assert_eq!(batch_header.transaction_ids[0], String::from("sig1"));
+    }
+
+    #[test]
+    fn generate_signed_batches_parallel_respects_max_batch_bytes() {
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+
+        write_txn_with_sig("sig1", &mut encoded_bytes);
+        write_txn_with_sig("sig2", &mut encoded_bytes);
+        write_txn_with_sig("sig3", &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+        let output_bytes: Vec<u8> = Vec::new();
+        let mut output = Cursor::new(output_bytes);
+
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        let txn_bytes = u64::from(make_txn("sig1").compute_size());
+        super::generate_signed_batches_parallel(
+            &mut source, &mut output, 10, &signer, 2, Some(txn_bytes), false,
+        ).expect("Should have generated batches!");
+
+        output.set_position(0);
+        let mut batch_source: BatchSource =
+            LengthDelimitedMessageSource::new(&mut output);
+
+        for sig in &["sig1", "sig2", "sig3"] {
+            let batch = &(batch_source.next(1).unwrap())[0];
+            let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+            assert_eq!(batch_header.transaction_ids.len(), 1);
+            assert_eq!(batch_header.transaction_ids[0], String::from(*sig));
+        }
+    }
+
+    #[test]
+    fn generate_signed_batches_parallel_verifies_transactions() {
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+        write_txn(&make_signed_txn("valid", &signer), &mut encoded_bytes);
+        // "sig1" is not a real signature over its header, so it should be dropped.
+        write_txn_with_sig("sig1", &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+        let output_bytes: Vec<u8> = Vec::new();
+        let mut output = Cursor::new(output_bytes);
+
+        super::generate_signed_batches_parallel(
+            &mut source, &mut output, 2, &signer, 2, None, true,
+        ).expect("Should have generated batches!");
+
+        output.set_position(0);
+        let mut batch_source: BatchSource =
+            LengthDelimitedMessageSource::new(&mut output);
+
+        let batch = &(batch_source.next(1).unwrap())[0];
+        let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+        assert_eq!(batch_header.transaction_ids.len(), 1);
+
+        assert!(batch_source.next(1).unwrap().is_empty());
+    }
+
     fn make_txn(sig: &str) -> Transaction {
         let mut txn_header = TransactionHeader::new();
 
@@ -345,4 +1047,78 @@ mod tests {
         let txn = make_txn(sig);
         txn.write_length_delimited_to_writer(out).expect("Unable to write delimiter");
     }
-}
\ No newline at end of file
+
+    /// Builds a transaction that is validly signed by `signer`, for tests
+    /// that exercise `set_verify_transactions`.
+    fn make_signed_txn(payload: &str, signer: &BatchSigner) -> Transaction {
+        let pubkey = signer.public_key().unwrap();
+
+        let mut txn_header = TransactionHeader::new();
+        txn_header.set_batcher_pubkey(pubkey.clone());
+        txn_header.set_family_name(String::from("test_family"));
+        txn_header.set_family_version(String::from("1.0"));
+        txn_header.set_signer_pubkey(pubkey);
+        txn_header.set_payload_encoding(String::from("text/string"));
+        txn_header.set_payload_sha512(String::from("some_sha512_hash"));
+
+        let header_bytes = txn_header.write_to_bytes().unwrap();
+        let header_signature = signer.sign(&header_bytes).unwrap();
+
+        let mut txn = Transaction::new();
+        txn.set_header(header_bytes);
+        txn.set_header_signature(header_signature);
+        txn.set_payload(payload.as_bytes().to_vec());
+
+        txn
+    }
+
+    fn write_txn(txn: &Transaction, out: &mut Write) {
+        txn.write_length_delimited_to_writer(out).expect("Unable to write delimiter");
+    }
+
+    #[test]
+    fn signed_batches_verify_drops_invalid_signatures() {
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+        write_txn(&make_signed_txn("valid", &signer), &mut encoded_bytes);
+        // "sig1" is not a real signature over its header, so it should be dropped.
+        write_txn_with_sig("sig1", &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+        let mut producer = SignedBatchProducer::new(&mut source, 2, &signer);
+        producer.set_verify_transactions();
+
+        let batch = producer.next_batch().unwrap().expect("expected a batch");
+        let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+        assert_eq!(batch_header.transaction_ids.len(), 1);
+
+        assert_eq!(producer.next_batch().unwrap(), None);
+        assert_eq!(producer.invalid_transaction_count(), 1);
+        assert_eq!(producer.duplicate_transaction_count(), 0);
+    }
+
+    #[test]
+    fn signed_batches_verify_drops_duplicate_transactions() {
+        let context = signing::create_context("secp256k1").unwrap();
+        let signer = test_signer(&*context);
+        let txn = make_signed_txn("repeated", &signer);
+
+        let mut encoded_bytes: Vec<u8> = Vec::new();
+        write_txn(&txn, &mut encoded_bytes);
+        write_txn(&txn, &mut encoded_bytes);
+
+        let mut source = Cursor::new(encoded_bytes);
+        let mut producer = SignedBatchProducer::new(&mut source, 2, &signer);
+        producer.set_verify_transactions();
+
+        let batch = producer.next_batch().unwrap().expect("expected a batch");
+        let batch_header: BatchHeader = protobuf::parse_from_bytes(&batch.header).unwrap();
+        assert_eq!(batch_header.transaction_ids.len(), 1);
+
+        assert_eq!(producer.next_batch().unwrap(), None);
+        assert_eq!(producer.invalid_transaction_count(), 0);
+        assert_eq!(producer.duplicate_transaction_count(), 1);
+    }
+}